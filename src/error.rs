@@ -0,0 +1,84 @@
+//! A path-carrying error type, so callers don't lose track of which path
+//! an operation failed on the way a bare `io::Error` does.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Maps common `io::ErrorKind`s to a short, lowercase human description,
+/// the way the libc error strings behind `std::fs` do. `None` for any
+/// other kind, so the caller can fall back to the original error's own
+/// message instead of discarding it.
+fn kind_message(kind: io::ErrorKind) -> Option<&'static str> {
+    match kind {
+        io::ErrorKind::NotFound => Some("no such file or directory"),
+        io::ErrorKind::PermissionDenied => Some("permission denied"),
+        io::ErrorKind::AlreadyExists => Some("file already exists"),
+        io::ErrorKind::InvalidInput => Some("invalid input"),
+        _ => None,
+    }
+}
+
+/// An `io::Error` that remembers which `Path` it happened to, so a
+/// mount resolving to the wrong place is actually debuggable.
+#[derive(Debug)]
+pub struct AfsError {
+    kind: io::ErrorKind,
+    message: String,
+    path: PathBuf,
+}
+
+impl AfsError {
+    pub fn new<M, P>(kind: io::ErrorKind, message: M, path: P) -> Self
+    where
+        M: Into<String>,
+        P: Into<PathBuf>,
+    {
+        AfsError {
+            kind: kind,
+            message: message.into(),
+            path: path.into(),
+        }
+    }
+
+    /// Wraps an `io::Error` surfaced by some underlying filesystem
+    /// implementation, attaching the path that was being operated on.
+    pub fn wrap<P>(err: io::Error, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        let kind = err.kind();
+        let message = match kind_message(kind) {
+            Some(message) => message.to_owned(),
+            None => err.to_string(),
+        };
+        AfsError::new(kind, message, path)
+    }
+}
+
+impl fmt::Display for AfsError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}: {}", self.message, self.path.display())
+    }
+}
+
+impl Error for AfsError {
+    fn description(&self) -> &str { &self.message }
+}
+
+impl From<AfsError> for io::Error {
+    fn from(err: AfsError) -> io::Error {
+        let kind = err.kind;
+        io::Error::new(kind, err.to_string())
+    }
+}
+
+/// Convenience for wrapping a `Result`'s `Err` side with the offending
+/// `path`.
+pub fn attach_path<T, P>(result: io::Result<T>, path: P) -> io::Result<T>
+where
+    P: AsRef<Path>,
+{
+    result.map_err(|err| AfsError::wrap(err, path.as_ref().to_owned()).into())
+}
@@ -1,3 +1,4 @@
+use ignore::WalkDir;
 use std::collections::BTreeMap;
 use std::fs;
 use std::io;
@@ -20,6 +21,10 @@ pub struct Metadata {
     pub created: Option<SystemTime>,
     pub accessed: Option<SystemTime>,
     pub modified: Option<SystemTime>,
+
+    /// The Unix permission bits (e.g. `0o644`), where available. `None`
+    /// on platforms (or backends) that don't have a concept of a mode.
+    pub mode: Option<u32>,
 }
 
 impl From<fs::Metadata> for Metadata {
@@ -35,10 +40,20 @@ impl From<fs::Metadata> for Metadata {
             created: meta.created().ok(),
             accessed: meta.accessed().ok(),
             modified: meta.modified().ok(),
+            mode: mode_of(&meta),
         }
     }
 }
 
+#[cfg(unix)]
+fn mode_of(meta: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode())
+}
+
+#[cfg(not(unix))]
+fn mode_of(_meta: &fs::Metadata) -> Option<u32> { None }
+
 pub trait File: io::Read + io::Seek + io::Write {
     fn metadata(&self) -> io::Result<Metadata>;
 }
@@ -50,7 +65,15 @@ bitflags! {
         const APPEND     = 0b0000_0100 | WRITE.bits,
         const TRUNCATE   = 0b0000_1000 | WRITE.bits,
         const CREATE     = 0b0001_0000 | WRITE.bits,
-        const CREATE_NEW = TRUNCATE.bits | WRITE.bits,
+        const CREATE_NEW = 0b0100_0000 | CREATE.bits,
+
+        /// Hints that the file should be served from a memory-mapped
+        /// region rather than `read(2)` syscalls, for read-mostly
+        /// workloads that repeatedly seek and re-read the same bytes.
+        /// Purely advisory: `StdFs` falls back to a regular file handle
+        /// whenever the backing path lives on a network filesystem, or
+        /// the mapping otherwise can't be established.
+        const MMAP       = 0b0010_0000 | READ.bits,
     }
 }
 
@@ -59,16 +82,87 @@ pub trait Filesystem: Send + Sync {
 
     fn metadata(&self, path: &Path) -> io::Result<Metadata>;
 
-    fn open_file(&self, path: &Path, opts: OpenOptions) -> io::Result<Box<File>>;
+    /// Opens (or creates, per `opts`) the file at `path`. `mode` sets the
+    /// Unix permission bits a newly-created file gets, the way
+    /// `OpenOptionsExt::mode` does; `None` leaves it to the platform
+    /// default (the umask). Ignored on platforms without a mode concept.
+    fn open_file(&self, path: &Path, opts: OpenOptions, mode: Option<u32>) -> io::Result<Box<File>>;
     fn remove_file(&self, path: &Path) -> io::Result<()>;
 
     fn read_dir(&self, path: &Path) -> io::Result<BTreeMap<PathBuf, Metadata>>;
-    fn create_dir(&self, path: &Path) -> io::Result<()>;
-    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// `mode` sets the new directory's Unix permission bits, the way
+    /// `DirBuilderExt::mode` does; `None` leaves it to the platform
+    /// default (the umask).
+    fn create_dir(&self, path: &Path, mode: Option<u32>) -> io::Result<()>;
+    fn create_dir_all(&self, path: &Path, mode: Option<u32>) -> io::Result<()>;
     fn remove_dir(&self, path: &Path) -> io::Result<()>;
     fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
 
-    // TODO fn copy(&self, from: &Path, to: &Path)  -> io::Result<()>;
-    // TODO fn move(&self, from: &Path, to: &Path)  -> io::Result<()>;
-    // TODO fn rename(&self, from: &Path, to: &Path)  -> io::Result<()>;
+    /// Copies the file or directory (recursively) at `from` to `to`. The
+    /// default implementation streams everything through `open_file`/
+    /// `read_dir`/`create_dir`, so it works between any two paths this
+    /// `Filesystem` can see, even ones backed by different transports.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> { generic_copy(self, from, to) }
+
+    /// Moves `from` to `to`. The default implementation is `copy` followed
+    /// by removing `from`, which is correct (if not free) even when `from`
+    /// and `to` ultimately live on different backends.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.copy(from, to)?;
+        if self.metadata(from)?.file_type.contains(DIRECTORY) {
+            self.remove_dir_all(from)
+        } else {
+            self.remove_file(from)
+        }
+    }
+
+    /// Recursively walks `path` depth-first, honoring any `.gitignore`/
+    /// `.ignore` files found along the way the same way a VCS working-copy
+    /// scanner would (innermost rules win, `!pattern` re-includes). An
+    /// ignored directory is not descended into. Entries are yielded in
+    /// sorted order per directory, and a failure to read one entry is
+    /// surfaced as an `Err` without aborting the rest of the walk.
+    ///
+    /// The default implementation is built entirely on `read_dir` and
+    /// `open_file`, so on `VirtualFs` it descends through whatever entries
+    /// `VirtualFs::read_dir` lists, including the `VDIR_META` entries it
+    /// synthesizes for mount points (a mount at `a/b` is listed from the
+    /// root as the single entry `a/b`, with no separate entry for `a`).
+    fn walk_dir<'a>(
+        &'a self,
+        path: &Path,
+    ) -> io::Result<Box<Iterator<Item = io::Result<(PathBuf, Metadata)>> + 'a>> {
+        Ok(Box::new(WalkDir::new(self, path)?))
+    }
+}
+
+/// Generic fallback for `Filesystem::copy`, streaming bytes through
+/// `open_file` and recursing through `read_dir`/`create_dir`. Used
+/// whenever there's no faster path-native way to move the data (e.g.
+/// `VirtualFs` copying between two different mounted backends).
+pub(crate) fn generic_copy<F: Filesystem + ?Sized>(fs: &F, from: &Path, to: &Path) -> io::Result<()> {
+    let meta = fs.metadata(from)?;
+    copy_entry(fs, from, to, &meta)
+}
+
+fn copy_entry<F: Filesystem + ?Sized>(
+    fs: &F,
+    from: &Path,
+    to: &Path,
+    meta: &Metadata,
+) -> io::Result<()> {
+    if meta.file_type.contains(DIRECTORY) {
+        fs.create_dir(to, meta.mode)?;
+        for (child, child_meta) in fs.read_dir(from)? {
+            let rel = child.strip_prefix(from).unwrap_or(&child);
+            copy_entry(fs, &child, &to.join(rel), &child_meta)?;
+        }
+        Ok(())
+    } else {
+        let mut src = fs.open_file(from, READ, None)?;
+        let mut dst = fs.open_file(to, CREATE | TRUNCATE, meta.mode)?;
+        io::copy(&mut src, &mut dst)?;
+        Ok(())
+    }
 }
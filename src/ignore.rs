@@ -0,0 +1,287 @@
+//! Minimal `.gitignore`/`.ignore` glob matching and the recursive
+//! `walk_dir` machinery used by `Filesystem::walk_dir`.
+
+use filesystem::*;
+use std::collections::btree_map;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::io;
+
+const IGNORE_FILE_NAMES: [&'static str; 2] = [".gitignore", ".ignore"];
+
+// ++++++++++++++++++++ glob matching ++++++++++++++++++++
+
+/// Matches `pat` against `s`, where `*` matches any run of bytes except
+/// `/`, `**` matches any run of bytes including `/`, and `?` matches any
+/// single byte except `/`.
+fn glob_match(pat: &[u8], s: &[u8]) -> bool {
+    match (pat.first(), s.first()) {
+        (None, None) => true,
+        (None, Some(_)) => false,
+        (Some(&b'*'), _) => {
+            if pat.get(1) == Some(&b'*') {
+                // A `**/` prefix must also be able to match zero path
+                // segments (e.g. `**/main.rs` matching a root-level
+                // `main.rs`), which requires trying the pattern past the
+                // separator directly, not just consuming bytes of `s`.
+                if pat.get(2) == Some(&b'/') && glob_match(&pat[3..], s) {
+                    return true;
+                }
+                glob_match(&pat[2..], s) || (!s.is_empty() && glob_match(pat, &s[1..]))
+            } else {
+                glob_match(&pat[1..], s) || (!s.is_empty() && s[0] != b'/' && glob_match(pat, &s[1..]))
+            }
+        }
+        (Some(&b'?'), Some(&c)) => c != b'/' && glob_match(&pat[1..], &s[1..]),
+        (Some(&pc), Some(&sc)) => pc == sc && glob_match(&pat[1..], &s[1..]),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone)]
+struct GlobPattern {
+    /// The raw pattern, stripped of its leading `!`, leading `/` and
+    /// trailing `/`.
+    pattern: String,
+    /// `true` if the pattern is rooted at the directory holding the
+    /// ignore file (it contained a `/` other than a trailing one).
+    anchored: bool,
+    /// `true` if the pattern only applies to directories (it had a
+    /// trailing `/`).
+    dir_only: bool,
+    /// `true` if this is a `!pattern` re-include rule.
+    negated: bool,
+}
+
+impl GlobPattern {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_right();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, line) = if line.starts_with('!') {
+            (true, &line[1..])
+        } else {
+            (false, line)
+        };
+
+        let dir_only = line.ends_with('/');
+        let line = if dir_only { &line[..line.len() - 1] } else { line };
+        if line.is_empty() {
+            return None;
+        }
+
+        let anchored = line[..line.len() - 1].contains('/') || line.starts_with('/');
+        let pattern = line.trim_left_matches('/').to_owned();
+
+        Some(GlobPattern {
+            pattern,
+            anchored,
+            dir_only,
+            negated,
+        })
+    }
+
+    /// `rel` is the entry's path relative to the directory that holds
+    /// the ignore file this pattern came from, using `/` separators.
+    fn matches(&self, rel: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        let pat = self.pattern.as_bytes();
+        if self.anchored {
+            glob_match(pat, rel.as_bytes())
+        } else {
+            // An un-anchored pattern may match starting at any path
+            // component boundary.
+            let mut rest = rel;
+            loop {
+                if glob_match(pat, rest.as_bytes()) {
+                    return true;
+                }
+                match rest.find('/') {
+                    Some(i) => rest = &rest[i + 1..],
+                    None => return false,
+                }
+            }
+        }
+    }
+}
+
+/// The compiled rules of a single `.gitignore`/`.ignore` file.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreMatcher {
+    patterns: Vec<GlobPattern>,
+}
+
+impl IgnoreMatcher {
+    fn parse(contents: &str) -> Self {
+        IgnoreMatcher {
+            patterns: contents.lines().filter_map(GlobPattern::parse).collect(),
+        }
+    }
+
+    /// `rel` is the path of the entry relative to the directory this
+    /// matcher was loaded from. Returns `Some(true)`/`Some(false)` if a
+    /// rule in this file matched (the last matching rule wins), or
+    /// `None` if no rule matched.
+    fn is_ignored(&self, rel: &str, is_dir: bool) -> Option<bool> {
+        let mut ignored = None;
+        for pattern in &self.patterns {
+            if pattern.matches(rel, is_dir) {
+                ignored = Some(!pattern.negated);
+            }
+        }
+        ignored
+    }
+}
+
+/// Reads and parses every ignore file present directly in `dir` (empty
+/// matcher if none are present).
+fn read_ignore_matcher<F: Filesystem + ?Sized>(fs: &F, dir: &Path) -> IgnoreMatcher {
+    let mut patterns = Vec::new();
+    for name in &IGNORE_FILE_NAMES {
+        let path = dir.join(name);
+        let mut file = match fs.open_file(&path, READ, None) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        let mut contents = String::new();
+        if file.read_to_string(&mut contents).is_ok() {
+            patterns.extend(IgnoreMatcher::parse(&contents).patterns);
+        }
+    }
+    IgnoreMatcher { patterns }
+}
+
+// ++++++++++++++++++++ WalkDir ++++++++++++++++++++
+
+struct Frame {
+    entries: btree_map::IntoIter<PathBuf, Metadata>,
+    has_matcher: bool,
+}
+
+/// Depth-first recursive directory walk that honors `.gitignore`/`.ignore`
+/// rules, as returned by `Filesystem::walk_dir`.
+pub struct WalkDir<'a, F: Filesystem + ?Sized + 'a> {
+    fs: &'a F,
+    stack: Vec<Frame>,
+    matchers: Vec<(PathBuf, IgnoreMatcher)>,
+}
+
+impl<'a, F: Filesystem + ?Sized + 'a> WalkDir<'a, F> {
+    pub fn new(fs: &'a F, root: &Path) -> io::Result<Self> {
+        let entries = fs.read_dir(root)?;
+        let mut walk = WalkDir {
+            fs: fs,
+            stack: Vec::new(),
+            matchers: Vec::new(),
+        };
+        walk.push(root.to_owned(), entries);
+        Ok(walk)
+    }
+
+    fn push(&mut self, dir: PathBuf, entries: ::std::collections::BTreeMap<PathBuf, Metadata>) {
+        let matcher = read_ignore_matcher(self.fs, &dir);
+        let has_matcher = !matcher.patterns.is_empty();
+        if has_matcher {
+            self.matchers.push((dir.clone(), matcher));
+        }
+        self.stack.push(Frame {
+            entries: entries.into_iter(),
+            has_matcher: has_matcher,
+        });
+    }
+
+    fn is_ignored(&self, vpath: &Path, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for &(ref base, ref matcher) in &self.matchers {
+            if let Ok(rel) = vpath.strip_prefix(base) {
+                if let Some(s) = rel.to_str() {
+                    if let Some(result) = matcher.is_ignored(s, is_dir) {
+                        ignored = result;
+                    }
+                }
+            }
+        }
+        ignored
+    }
+}
+
+impl<'a, F: Filesystem + ?Sized + 'a> Iterator for WalkDir<'a, F> {
+    type Item = io::Result<(PathBuf, Metadata)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.stack.last_mut() {
+                None => return None,
+                Some(frame) => frame.entries.next(),
+            };
+
+            let (vpath, meta) = match entry {
+                Some(entry) => entry,
+                None => {
+                    let frame = self.stack.pop().unwrap();
+                    if frame.has_matcher {
+                        self.matchers.pop();
+                    }
+                    continue;
+                }
+            };
+
+            let is_dir = meta.file_type.contains(DIRECTORY);
+            if self.is_ignored(&vpath, is_dir) {
+                continue;
+            }
+
+            if is_dir {
+                match self.fs.read_dir(&vpath) {
+                    Ok(entries) => self.push(vpath.clone(), entries),
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+            return Some(Ok((vpath, meta)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn star_does_not_cross_slash() {
+        assert!(glob_match(b"*.rs", b"main.rs"));
+        assert!(!glob_match(b"*.rs", b"src/main.rs"));
+    }
+
+    #[test]
+    fn double_star_crosses_slash() {
+        assert!(glob_match(b"**/main.rs", b"src/deep/main.rs"));
+        assert!(glob_match(b"**/main.rs", b"main.rs"));
+    }
+
+    #[test]
+    fn last_matching_rule_wins() {
+        let matcher = IgnoreMatcher::parse("*.log\n!keep.log\n");
+        assert_eq!(matcher.is_ignored("debug.log", false), Some(true));
+        assert_eq!(matcher.is_ignored("keep.log", false), Some(false));
+        assert_eq!(matcher.is_ignored("README.md", false), None);
+    }
+
+    #[test]
+    fn dir_only_pattern_ignores_only_directories() {
+        let matcher = IgnoreMatcher::parse("build/\n");
+        assert_eq!(matcher.is_ignored("build", false), None);
+        assert_eq!(matcher.is_ignored("build", true), Some(true));
+    }
+
+    #[test]
+    fn anchored_pattern_only_matches_at_root() {
+        let matcher = IgnoreMatcher::parse("/target\n");
+        assert_eq!(matcher.is_ignored("target", false), Some(true));
+        assert_eq!(matcher.is_ignored("nested/target", false), None);
+    }
+}
@@ -3,17 +3,25 @@
 #[macro_use]
 extern crate bitflags;
 extern crate lazy_static;
+extern crate libc;
+extern crate memmap;
 extern crate tempdir;
 #[cfg(feature = "xdg")]
 extern crate xdg;
 
 // ++++++++++++++++++++ submodules ++++++++++++++++++++
 
+mod error;
 mod filesystem;
+mod ignore;
+mod p9_fs;
 mod std_fs;
 mod virtual_fs;
 
+pub use error::*;
 pub use filesystem::*;
+pub use ignore::*;
+pub use p9_fs::*;
 pub use std_fs::*;
 pub use virtual_fs::*;
 
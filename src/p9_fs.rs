@@ -0,0 +1,665 @@
+//! A 9P2000.L client, usable as a `Filesystem` (and therefore `mount`able
+//! into a `VirtualFs` alongside `StdFs`). This is the crate's first real
+//! network/remote backend.
+//!
+//! Only a single in-flight transaction is supported: every `Filesystem`
+//! method takes the connection's lock for the whole request/reply
+//! round-trip. Each request gets its own tag and its reply must carry
+//! the same tag; replies are never accepted out of order.
+
+use filesystem::*;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// ++++++++++++++++++++ wire protocol ++++++++++++++++++++
+
+const MSIZE: u32 = 64 * 1024;
+const PROTOCOL_VERSION: &'static str = "9P2000.L";
+const NOFID: u32 = !0;
+
+// 9P2000.L message types. Each `T*` request below is answered by either
+// `T* + 1` (the matching `R*` reply, checked in `Conn::transaction`) or
+// an `RLERROR`.
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TREADDIR: u8 = 40;
+const TMKDIR: u8 = 72;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+// Linux open(2) flags, as used by `Tlopen`/`Tlcreate`.
+const O_WRONLY: u32 = 0o1;
+const O_RDWR: u32 = 0o2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+const O_DIRECTORY: u32 = 0o200000;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+/// `unlinkat(2)` flag requesting that the target be removed as a
+/// directory.
+const AT_REMOVEDIR: u32 = 0x200;
+
+/// The subset of `Tgetattr`'s `request_mask` fields the crate cares
+/// about (mode, nlink, uid/gid, rdev, atime, mtime, ctime, ino, size,
+/// blocks): good enough to fill in `Metadata`.
+const GETATTR_BASIC: u64 = 0x0000_07ff;
+
+#[derive(Debug, Clone, Copy)]
+struct Qid {
+    qtype: u8,
+    version: u32,
+    path: u64,
+}
+
+// ++++++++++++++++++++ encode/decode helpers ++++++++++++++++++++
+
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn new() -> Self { Encoder(Vec::new()) }
+    fn u8(&mut self, v: u8) -> &mut Self { self.0.push(v); self }
+    fn u16(&mut self, v: u16) -> &mut Self {
+        self.0.extend_from_slice(&[v as u8, (v >> 8) as u8]);
+        self
+    }
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.0.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+        self
+    }
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.u32(v as u32);
+        self.u32((v >> 32) as u32);
+        self
+    }
+    fn str(&mut self, s: &str) -> &mut Self {
+        self.u16(s.len() as u16);
+        self.0.extend_from_slice(s.as_bytes());
+        self
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Self { Decoder { buf: buf, pos: 0 } }
+
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.pos + n > self.buf.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated 9P message"));
+        }
+        let slice = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+    fn u8(&mut self) -> io::Result<u8> { Ok(self.take(1)?[0]) }
+    fn u16(&mut self) -> io::Result<u16> {
+        let b = self.take(2)?;
+        Ok(b[0] as u16 | (b[1] as u16) << 8)
+    }
+    fn u32(&mut self) -> io::Result<u32> {
+        let b = self.take(4)?;
+        Ok(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+    }
+    fn u64(&mut self) -> io::Result<u64> {
+        let lo = self.u32()? as u64;
+        let hi = self.u32()? as u64;
+        Ok(lo | hi << 32)
+    }
+    fn qid(&mut self) -> io::Result<Qid> {
+        Ok(Qid {
+            qtype: self.u8()?,
+            version: self.u32()?,
+            path: self.u64()?,
+        })
+    }
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u16()? as usize;
+        let bytes = self.take(len)?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+// ++++++++++++++++++++ transport ++++++++++++++++++++
+
+enum Transport {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.read(buf),
+            #[cfg(unix)]
+            Transport::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.write(buf),
+            #[cfg(unix)]
+            Transport::Unix(ref mut s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.flush(),
+            #[cfg(unix)]
+            Transport::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+struct Conn {
+    transport: Transport,
+    msize: u32,
+    next_tag: u16,
+    next_fid: u32,
+}
+
+impl Conn {
+    /// Sends `mtype`/`body` as a single 9P message and returns the body
+    /// of the matching reply, after checking for `Rlerror`.
+    fn transaction(&mut self, mtype: u8, body: &[u8]) -> io::Result<Vec<u8>> {
+        let tag = self.next_tag;
+        self.next_tag = self.next_tag.wrapping_add(1);
+
+        let size = 4 + 1 + 2 + body.len() as u32;
+        let mut header = Encoder::new();
+        header.u32(size).u8(mtype).u16(tag);
+        self.transport.write_all(&header.0)?;
+        self.transport.write_all(body)?;
+        self.transport.flush()?;
+
+        let mut size_buf = [0u8; 4];
+        self.transport.read_exact(&mut size_buf)?;
+        let size = Decoder::new(&size_buf).u32()? as usize;
+        if size < 7 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed 9P message"));
+        }
+        let mut rest = vec![0u8; size - 4];
+        self.transport.read_exact(&mut rest)?;
+
+        let mut dec = Decoder::new(&rest);
+        let rtype = dec.u8()?;
+        let rtag = dec.u16()?;
+        if rtag != tag {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "9P reply tag does not match request"));
+        }
+        let body = rest[3..].to_vec();
+
+        if rtype == RLERROR {
+            let errno = Decoder::new(&body).u32()?;
+            return Err(io::Error::from_raw_os_error(errno as i32));
+        }
+        if rtype != mtype + 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected 9P reply type"));
+        }
+        Ok(body)
+    }
+
+    fn alloc_fid(&mut self) -> u32 {
+        let fid = self.next_fid;
+        self.next_fid += 1;
+        fid
+    }
+
+    /// Clones `fid` onto a new fid walked down to `path`. An empty path
+    /// just clones `fid` (a zero-element `Twalk`).
+    fn walk(&mut self, fid: u32, path: &Path) -> io::Result<u32> {
+        let newfid = self.alloc_fid();
+        let names: Vec<String> = path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect();
+
+        let mut enc = Encoder::new();
+        enc.u32(fid).u32(newfid).u16(names.len() as u16);
+        for name in &names {
+            enc.str(name);
+        }
+        let body = self.transaction(TWALK, &enc.0)?;
+
+        let mut dec = Decoder::new(&body);
+        let nwqid = dec.u16()?;
+        if nwqid as usize != names.len() {
+            let _ = self.clunk(newfid);
+            return Err(io::Error::new(io::ErrorKind::NotFound, "9P walk did not reach target"));
+        }
+        Ok(newfid)
+    }
+
+    fn clunk(&mut self, fid: u32) -> io::Result<()> {
+        let mut enc = Encoder::new();
+        enc.u32(fid);
+        self.transaction(TCLUNK, &enc.0).map(|_| ())
+    }
+
+    fn getattr(&mut self, fid: u32) -> io::Result<Metadata> {
+        let mut enc = Encoder::new();
+        enc.u32(fid).u64(GETATTR_BASIC);
+        let body = self.transaction(TGETATTR, &enc.0)?;
+
+        let mut dec = Decoder::new(&body);
+        let _valid = dec.u64()?;
+        let _qid = dec.qid()?;
+        let mode = dec.u32()?;
+        let _uid = dec.u32()?;
+        let _gid = dec.u32()?;
+        let _nlink = dec.u64()?;
+        let _rdev = dec.u64()?;
+        let size = dec.u64()?;
+        let _blksize = dec.u64()?;
+        let _blocks = dec.u64()?;
+        let atime = system_time(dec.u64()?, dec.u64()?);
+        let mtime = system_time(dec.u64()?, dec.u64()?);
+        let ctime = system_time(dec.u64()?, dec.u64()?);
+
+        let file_type = if mode & S_IFMT == S_IFDIR { DIRECTORY } else { FILE };
+        Ok(Metadata {
+            is_readonly: mode & 0o222 == 0,
+            file_type: file_type,
+            len: Some(size),
+            created: Some(ctime),
+            accessed: Some(atime),
+            modified: Some(mtime),
+            mode: Some(mode & 0o7777),
+        })
+    }
+}
+
+fn system_time(secs: u64, nanos: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::new(secs, nanos as u32)
+}
+
+fn open_flags(opts: OpenOptions) -> u32 {
+    let mut flags = if opts.contains(WRITE) {
+        if opts.contains(READ) { O_RDWR } else { O_WRONLY }
+    } else {
+        0
+    };
+    if opts.contains(APPEND) {
+        flags |= O_APPEND;
+    }
+    if opts.contains(TRUNCATE) {
+        flags |= O_TRUNC;
+    }
+    if opts.contains(CREATE) {
+        flags |= O_CREAT;
+    }
+    if opts.contains(CREATE_NEW) {
+        flags |= O_CREAT | O_EXCL;
+    }
+    flags
+}
+
+// ++++++++++++++++++++ P9Fs ++++++++++++++++++++
+
+/// A `Filesystem` backed by a remote 9P2000.L file server.
+pub struct P9Fs {
+    conn: Arc<Mutex<Conn>>,
+    root_fid: u32,
+}
+
+impl P9Fs {
+    fn handshake(mut transport: Transport, uname: &str, aname: &str) -> io::Result<Self> {
+        let mut conn = Conn {
+            transport: transport,
+            msize: MSIZE,
+            next_tag: 0,
+            next_fid: 1,
+        };
+
+        let mut enc = Encoder::new();
+        enc.u32(MSIZE).str(PROTOCOL_VERSION);
+        let body = conn.transaction(TVERSION, &enc.0)?;
+        let mut dec = Decoder::new(&body);
+        let msize = dec.u32()?;
+        let version = dec.string()?;
+        if version != PROTOCOL_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("9P server does not support {}", PROTOCOL_VERSION),
+            ));
+        }
+        conn.msize = msize;
+
+        let root_fid = conn.alloc_fid();
+        let mut enc = Encoder::new();
+        enc.u32(root_fid).u32(NOFID).str(uname).str(aname).u32(!0u32);
+        conn.transaction(TATTACH, &enc.0)?;
+
+        Ok(P9Fs {
+            conn: Arc::new(Mutex::new(conn)),
+            root_fid: root_fid,
+        })
+    }
+
+    /// Connects over TCP and attaches as `uname` to the export `aname`.
+    pub fn connect_tcp<A: ToSocketAddrs>(addr: A, uname: &str, aname: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Self::handshake(Transport::Tcp(stream), uname, aname)
+    }
+
+    /// Connects over a Unix domain socket and attaches as `uname` to the
+    /// export `aname`.
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P, uname: &str, aname: &str) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        Self::handshake(Transport::Unix(stream), uname, aname)
+    }
+
+    /// Walks from the root fid down to `vpath`, returning a fresh fid the
+    /// caller is responsible for clunking.
+    fn walk_from_root(&self, vpath: &Path) -> io::Result<u32> {
+        let mut conn = self.conn.lock().unwrap();
+        let root_fid = self.root_fid;
+        conn.walk(root_fid, vpath)
+    }
+}
+
+impl Drop for P9Fs {
+    fn drop(&mut self) {
+        let mut conn = self.conn.lock().unwrap();
+        let root_fid = self.root_fid;
+        let _ = conn.clunk(root_fid);
+    }
+}
+
+impl Filesystem for P9Fs {
+    fn metadata(&self, vpath: &Path) -> io::Result<Metadata> {
+        let _ = super::validate_path(vpath)?;
+
+        let fid = self.walk_from_root(vpath)?;
+        let mut conn = self.conn.lock().unwrap();
+        let result = conn.getattr(fid);
+        let _ = conn.clunk(fid);
+        result
+    }
+
+    fn open_file(&self, vpath: &Path, opts: OpenOptions, mode: Option<u32>) -> io::Result<Box<File>> {
+        let _ = super::validate_path(vpath)?;
+
+        let flags = open_flags(opts);
+        match self.walk_from_root(vpath) {
+            Ok(fid) => {
+                let mut conn = self.conn.lock().unwrap();
+                if opts.contains(CREATE_NEW) {
+                    let _ = conn.clunk(fid);
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file already exists"));
+                }
+
+                let mut enc = Encoder::new();
+                enc.u32(fid).u32(flags);
+                if let Err(err) = conn.transaction(TLOPEN, &enc.0) {
+                    let _ = conn.clunk(fid);
+                    return Err(err);
+                }
+                let msize = conn.msize;
+                Ok(Box::new(P9File {
+                    conn: self.conn.clone(),
+                    fid: fid,
+                    cursor: 0,
+                    msize: msize,
+                }))
+            }
+            Err(ref err)
+                if err.kind() == io::ErrorKind::NotFound
+                    && (opts.contains(CREATE) || opts.contains(CREATE_NEW)) =>
+            {
+                let (parent, name) = split_parent(vpath)?;
+                let parent_fid = self.walk_from_root(&parent)?;
+                let mut conn = self.conn.lock().unwrap();
+
+                // `Tlcreate` turns `parent_fid` itself into the freshly
+                // opened file's fid.
+                let mut enc = Encoder::new();
+                enc.u32(parent_fid).str(&name).u32(flags).u32(mode.unwrap_or(0o644)).u32(0);
+                if let Err(err) = conn.transaction(TLCREATE, &enc.0) {
+                    let _ = conn.clunk(parent_fid);
+                    return Err(err);
+                }
+                let msize = conn.msize;
+                Ok(Box::new(P9File {
+                    conn: self.conn.clone(),
+                    fid: parent_fid,
+                    cursor: 0,
+                    msize: msize,
+                }))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn remove_file(&self, vpath: &Path) -> io::Result<()> { self.unlink(vpath, 0) }
+
+    fn read_dir(&self, vpath: &Path) -> io::Result<BTreeMap<PathBuf, Metadata>> {
+        let _ = super::validate_path(vpath)?;
+
+        let dirfid = self.walk_from_root(vpath)?;
+        let mut conn = self.conn.lock().unwrap();
+
+        let mut enc = Encoder::new();
+        enc.u32(dirfid).u32(O_DIRECTORY);
+        if let Err(err) = conn.transaction(TLOPEN, &enc.0) {
+            let _ = conn.clunk(dirfid);
+            return Err(err);
+        }
+
+        let mut names = Vec::new();
+        let mut offset = 0u64;
+        loop {
+            let mut enc = Encoder::new();
+            enc.u32(dirfid).u64(offset).u32(conn.msize);
+            let body = match conn.transaction(TREADDIR, &enc.0) {
+                Ok(body) => body,
+                Err(err) => {
+                    let _ = conn.clunk(dirfid);
+                    return Err(err);
+                }
+            };
+            let mut dec = Decoder::new(&body);
+            let count = dec.u32()? as usize;
+            if count == 0 {
+                break;
+            }
+            let mut read = 0usize;
+            while read < count {
+                let _qid = dec.qid()?;
+                offset = dec.u64()?;
+                let _dtype = dec.u8()?;
+                let name = dec.string()?;
+                read = dec.pos - 4;
+                if name != "." && name != ".." {
+                    names.push(name);
+                }
+            }
+        }
+        let _ = conn.clunk(dirfid);
+
+        let mut ret = BTreeMap::new();
+        for name in names {
+            let child_vpath = vpath.join(&name);
+            let fid = conn.walk(self.root_fid, &child_vpath)?;
+            let meta = conn.getattr(fid);
+            let _ = conn.clunk(fid);
+            ret.insert(child_vpath, meta?);
+        }
+        Ok(ret)
+    }
+
+    fn create_dir(&self, vpath: &Path, mode: Option<u32>) -> io::Result<()> {
+        let _ = super::validate_path(vpath)?;
+        self.mkdir(vpath, mode)
+    }
+
+    fn create_dir_all(&self, vpath: &Path, mode: Option<u32>) -> io::Result<()> {
+        let _ = super::validate_path(vpath)?;
+
+        let mut built = PathBuf::new();
+        for component in vpath.components() {
+            built.push(component);
+            match self.mkdir(&built, mode) {
+                Ok(()) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_dir(&self, vpath: &Path) -> io::Result<()> { self.unlink(vpath, AT_REMOVEDIR) }
+
+    fn remove_dir_all(&self, vpath: &Path) -> io::Result<()> {
+        let _ = super::validate_path(vpath)?;
+
+        for (child, meta) in self.read_dir(vpath)? {
+            if meta.file_type.contains(DIRECTORY) {
+                self.remove_dir_all(&child)?;
+            } else {
+                self.remove_file(&child)?;
+            }
+        }
+        self.remove_dir(vpath)
+    }
+}
+
+impl P9Fs {
+    /// `Tunlinkat` the last component of `vpath` out of its parent fid.
+    fn unlink(&self, vpath: &Path, flags: u32) -> io::Result<()> {
+        let _ = super::validate_path(vpath)?;
+
+        let (parent, name) = split_parent(vpath)?;
+        let parent_fid = self.walk_from_root(&parent)?;
+        let mut conn = self.conn.lock().unwrap();
+
+        let mut enc = Encoder::new();
+        enc.u32(parent_fid).str(&name).u32(flags);
+        let result = conn.transaction(TUNLINKAT, &enc.0).map(|_| ());
+        let _ = conn.clunk(parent_fid);
+        result
+    }
+
+    fn mkdir(&self, vpath: &Path, mode: Option<u32>) -> io::Result<()> {
+        let (parent, name) = split_parent(vpath)?;
+        let parent_fid = self.walk_from_root(&parent)?;
+        let mut conn = self.conn.lock().unwrap();
+
+        let mut enc = Encoder::new();
+        enc.u32(parent_fid).str(&name).u32(mode.unwrap_or(0o755)).u32(0);
+        let result = conn.transaction(TMKDIR, &enc.0).map(|_| ());
+        let _ = conn.clunk(parent_fid);
+        result
+    }
+}
+
+fn split_parent(vpath: &Path) -> io::Result<(PathBuf, String)> {
+    let name = vpath
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty path"))?
+        .to_string_lossy()
+        .into_owned();
+    let parent = vpath.parent().unwrap_or(Path::new("")).to_owned();
+    Ok((parent, name))
+}
+
+// ++++++++++++++++++++ P9File ++++++++++++++++++++
+
+pub struct P9File {
+    conn: Arc<Mutex<Conn>>,
+    fid: u32,
+    cursor: u64,
+    msize: u32,
+}
+
+impl io::Read for P9File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        // Leave room for the `Rread` header (size/type/tag/count).
+        let want = ::std::cmp::min(buf.len(), self.msize as usize - 4 - 7);
+
+        let mut enc = Encoder::new();
+        enc.u32(self.fid).u64(self.cursor).u32(want as u32);
+        let body = conn.transaction(TREAD, &enc.0)?;
+
+        let mut dec = Decoder::new(&body);
+        let count = dec.u32()? as usize;
+        let data = dec.take(count)?;
+        buf[..count].copy_from_slice(data);
+        self.cursor += count as u64;
+        Ok(count)
+    }
+}
+
+impl io::Write for P9File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let want = ::std::cmp::min(buf.len(), self.msize as usize - 4 - 7 - 8 - 4);
+
+        let mut enc = Encoder::new();
+        enc.u32(self.fid).u64(self.cursor).u32(want as u32);
+        enc.0.extend_from_slice(&buf[..want]);
+        let body = conn.transaction(TWRITE, &enc.0)?;
+
+        let count = Decoder::new(&body).u32()? as usize;
+        self.cursor += count as u64;
+        Ok(count)
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl io::Seek for P9File {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let new_cursor = match pos {
+            io::SeekFrom::Start(n) => n as i64,
+            io::SeekFrom::Current(n) => self.cursor as i64 + n,
+            io::SeekFrom::End(n) => self.metadata()?.len.unwrap_or(0) as i64 + n,
+        };
+        if new_cursor < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file"));
+        }
+        self.cursor = new_cursor as u64;
+        Ok(self.cursor)
+    }
+}
+
+impl File for P9File {
+    fn metadata(&self) -> io::Result<Metadata> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.getattr(self.fid)
+    }
+}
+
+impl Drop for P9File {
+    fn drop(&mut self) {
+        let mut conn = self.conn.lock().unwrap();
+        let _ = conn.clunk(self.fid);
+    }
+}
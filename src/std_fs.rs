@@ -1,6 +1,9 @@
+use error::attach_path;
 use filesystem::*;
+use memmap::{Mmap, Protection};
 use std::collections::BTreeMap;
 use std::error::Error;
+use std::io::Seek;
 use std::path::{Path, PathBuf};
 use std::{env, fs, io};
 
@@ -98,34 +101,174 @@ fn canonicalize(
 
 // ++++++++++++++++++++ StdFile ++++++++++++++++++++
 
-pub struct StdFile(fs::File);
+/// Either a plain file handle, or one additionally backed by a read-only
+/// `mmap` of its contents (requested via `OpenOptions::MMAP`). Writes
+/// always go through `file` directly, never through the mapping.
+pub enum StdFile {
+    Direct(fs::File),
+    Mapped {
+        file: fs::File,
+        mmap: Mmap,
+        pos: usize,
+    },
+}
 
 impl From<fs::File> for StdFile {
-    fn from(file: fs::File) -> Self { StdFile(file) }
+    fn from(file: fs::File) -> Self { StdFile::Direct(file) }
 }
 
 // TODO?
 //impl Drop for StdFile {
-//    fn drop(&mut self){ self.0.sync_all();  }
+//    fn drop(&mut self){ self.file().sync_all();  }
 //}
 
+impl StdFile {
+    fn file(&self) -> &fs::File {
+        match *self {
+            StdFile::Direct(ref file) => file,
+            StdFile::Mapped { ref file, .. } => file,
+        }
+    }
+}
+
 impl io::Read for StdFile {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            StdFile::Direct(ref mut file) => file.read(buf),
+            StdFile::Mapped {
+                ref mmap,
+                ref mut pos,
+                ..
+            } => {
+                let whole = unsafe { mmap.as_slice() };
+                if *pos >= whole.len() {
+                    return Ok(0);
+                }
+                let src = &whole[*pos..];
+                let n = ::std::cmp::min(buf.len(), src.len());
+                buf[..n].copy_from_slice(&src[..n]);
+                *pos += n;
+                Ok(n)
+            }
+        }
+    }
 }
 
 impl io::Seek for StdFile {
-    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> { self.0.seek(pos) }
+    fn seek(&mut self, seek_pos: io::SeekFrom) -> io::Result<u64> {
+        match *self {
+            StdFile::Direct(ref mut file) => file.seek(seek_pos),
+            StdFile::Mapped {
+                ref mmap,
+                ref mut pos,
+                ..
+            } => {
+                let new_pos = match seek_pos {
+                    io::SeekFrom::Start(p) => p as i64,
+                    io::SeekFrom::End(p) => mmap.len() as i64 + p,
+                    io::SeekFrom::Current(p) => *pos as i64 + p,
+                };
+                if new_pos < 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "invalid seek to a negative position",
+                    ));
+                }
+                *pos = new_pos as usize;
+                Ok(new_pos as u64)
+            }
+        }
+    }
 }
 
 impl io::Write for StdFile {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.write(buf) }
-    fn flush(&mut self) -> io::Result<()> { self.0.flush() }
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            StdFile::Direct(ref mut file) => file.write(buf),
+            StdFile::Mapped {
+                ref mut file,
+                ref mut pos,
+                ..
+            } => {
+                file.seek(io::SeekFrom::Start(*pos as u64))?;
+                let n = file.write(buf)?;
+                *pos += n;
+                Ok(n)
+            }
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            StdFile::Direct(ref mut file) => file.flush(),
+            StdFile::Mapped { ref mut file, .. } => file.flush(),
+        }
+    }
 }
 
 impl File for StdFile {
-    fn metadata(&self) -> io::Result<Metadata> { self.0.metadata().map(|meta| meta.into()) }
+    fn metadata(&self) -> io::Result<Metadata> { self.file().metadata().map(|meta| meta.into()) }
+}
+
+/// Checks (on platforms where we know how) whether `path` lives on a
+/// network filesystem such as NFS or CIFS/SMB, where `mmap`ing a file is
+/// unsafe: a stale or server-side-truncated mapping can fault the
+/// process instead of returning an I/O error.
+#[cfg(unix)]
+fn is_network_fs(path: &Path) -> bool {
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    const NFS_SUPER_MAGIC: i64 = 0x6969;
+    const SMB_SUPER_MAGIC: i64 = 0x517b;
+    const CIFS_MAGIC_NUMBER: i64 = 0xff534d42u32 as i64;
+
+    let c_path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c_path) => c_path,
+        Err(_) => return false,
+    };
+
+    unsafe {
+        let mut buf: libc::statfs = mem::zeroed();
+        if libc::statfs(c_path.as_ptr(), &mut buf) != 0 {
+            return false;
+        }
+        let f_type = buf.f_type as i64;
+        f_type == NFS_SUPER_MAGIC || f_type == SMB_SUPER_MAGIC || f_type == CIFS_MAGIC_NUMBER
+    }
+}
+
+#[cfg(not(unix))]
+fn is_network_fs(_path: &Path) -> bool { false }
+
+/// Applies `mode` (if any) as the Unix permission bits a newly-created
+/// file gets. A no-op on platforms without a mode concept and on any
+/// platform when `mode` is `None` (the umask applies as usual).
+#[cfg(unix)]
+fn set_open_mode(opts: &mut fs::OpenOptions, mode: Option<u32>) {
+    use std::os::unix::fs::OpenOptionsExt;
+    if let Some(mode) = mode {
+        opts.mode(mode);
+    }
 }
 
+#[cfg(not(unix))]
+fn set_open_mode(_opts: &mut fs::OpenOptions, _mode: Option<u32>) {}
+
+/// Applies `mode` (if any) as the Unix permission bits a newly-created
+/// directory gets. A no-op on platforms without a mode concept and on
+/// any platform when `mode` is `None` (the umask applies as usual).
+#[cfg(unix)]
+fn set_dir_mode(builder: &mut fs::DirBuilder, mode: Option<u32>) {
+    use std::os::unix::fs::DirBuilderExt;
+    if let Some(mode) = mode {
+        builder.mode(mode);
+    }
+}
+
+#[cfg(not(unix))]
+fn set_dir_mode(_builder: &mut fs::DirBuilder, _mode: Option<u32>) {}
+
 // ++++++++++++++++++++ StdFs ++++++++++++++++++++
 
 /// TODO Naming? OsFs?
@@ -174,56 +317,123 @@ impl Filesystem for StdFs {
     fn metadata(&self, vpath: &Path) -> io::Result<Metadata> {
         let _ = super::validate_path(vpath)?;
 
-        fs::metadata(self.base.join(vpath)).map(|meta| meta.into())
+        let real_path = self.base.join(vpath);
+        attach_path(fs::metadata(&real_path), &real_path).map(|meta| meta.into())
     }
-    fn open_file(&self, vpath: &Path, opts: OpenOptions) -> io::Result<Box<File>> {
+    fn open_file(&self, vpath: &Path, opts: OpenOptions, mode: Option<u32>) -> io::Result<Box<File>> {
         let _ = super::validate_path(vpath)?;
 
-        let file = fs::OpenOptions::new()
+        let real_path = self.base.join(vpath);
+        let mut std_opts = fs::OpenOptions::new();
+        std_opts
             .read(opts.contains(READ))
             .write(opts.contains(WRITE))
             .append(opts.contains(APPEND))
             .truncate(opts.contains(TRUNCATE))
             .create(opts.contains(CREATE))
-            .create_new(opts.contains(CREATE_NEW))
-            .open(self.base.join(vpath))?;
-        Ok(Box::new(StdFile(file)))
+            .create_new(opts.contains(CREATE_NEW));
+        set_open_mode(&mut std_opts, mode);
+        let file = attach_path(std_opts.open(&real_path), &real_path)?;
+
+        if opts.contains(MMAP) && !is_network_fs(&real_path) {
+            let len = attach_path(file.metadata(), &real_path)?.len();
+            if len > 0 {
+                if let Ok(mmap) = Mmap::open(&file, Protection::Read) {
+                    return Ok(Box::new(StdFile::Mapped { file, mmap, pos: 0 }));
+                }
+            }
+        }
+        Ok(Box::new(StdFile::Direct(file)))
     }
     fn remove_file(&self, vpath: &Path) -> io::Result<()> {
         let _ = super::validate_path(vpath)?;
 
-        fs::remove_file(self.base.join(vpath))
+        let real_path = self.base.join(vpath);
+        attach_path(fs::remove_file(&real_path), &real_path)
     }
     fn read_dir(&self, vpath: &Path) -> io::Result<BTreeMap<PathBuf, Metadata>> {
         let _ = super::validate_path(vpath)?;
 
+        let real_path = self.base.join(vpath);
         let mut ret = BTreeMap::new();
-        for entry in fs::read_dir(self.base.join(vpath))? {
-            let entry = entry?;
+        for entry in attach_path(fs::read_dir(&real_path), &real_path)? {
+            let entry = attach_path(entry, &real_path)?;
             let vpath = entry.path().strip_prefix(&self.base).unwrap().to_owned();
-            let meta = Metadata::from(entry.metadata()?);
-            ret.insert(vpath, meta);
+            let meta = attach_path(entry.metadata(), &entry.path())?;
+            ret.insert(vpath, Metadata::from(meta));
         }
         Ok(ret)
     }
-    fn create_dir(&self, vpath: &Path) -> io::Result<()> {
+    fn create_dir(&self, vpath: &Path, mode: Option<u32>) -> io::Result<()> {
         let _ = super::validate_path(vpath)?;
 
-        fs::create_dir(self.base.join(vpath))
+        let real_path = self.base.join(vpath);
+        let mut builder = fs::DirBuilder::new();
+        set_dir_mode(&mut builder, mode);
+        attach_path(builder.create(&real_path), &real_path)
     }
-    fn create_dir_all(&self, vpath: &Path) -> io::Result<()> {
+    fn create_dir_all(&self, vpath: &Path, mode: Option<u32>) -> io::Result<()> {
         let _ = super::validate_path(vpath)?;
 
-        fs::create_dir_all(self.base.join(vpath))
+        let real_path = self.base.join(vpath);
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        set_dir_mode(&mut builder, mode);
+        attach_path(builder.create(&real_path), &real_path)
     }
     fn remove_dir(&self, vpath: &Path) -> io::Result<()> {
         let _ = super::validate_path(vpath)?;
 
-        fs::remove_dir(self.base.join(vpath))
+        let real_path = self.base.join(vpath);
+        attach_path(fs::remove_dir(&real_path), &real_path)
+    }
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let _ = super::validate_path(from)?;
+        let _ = super::validate_path(to)?;
+
+        let from_real = self.base.join(from);
+        let to_real = self.base.join(to);
+        // `std::fs::copy` only works for regular files; fall back to the
+        // generic streaming recursive copy for directories.
+        if attach_path(fs::metadata(&from_real), &from_real)?.is_dir() {
+            return generic_copy(self, from, to);
+        }
+        attach_path(fs::copy(&from_real, &to_real).map(|_| ()), &from_real)
+    }
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let _ = super::validate_path(from)?;
+        let _ = super::validate_path(to)?;
+
+        let from_real = self.base.join(from);
+        let to_real = self.base.join(to);
+        attach_path(fs::rename(&from_real, &to_real), &from_real)
     }
     fn remove_dir_all(&self, vpath: &Path) -> io::Result<()> {
         let _ = super::validate_path(vpath)?;
 
-        fs::remove_dir_all(self.base.join(vpath))
+        let real_path = self.base.join(vpath);
+        attach_path(fs::remove_dir_all(&real_path), &real_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use tempdir::TempDir;
+
+    #[test]
+    fn mmap_read_past_eof_returns_empty_instead_of_panicking() {
+        let tmp = TempDir::new("afs-std-fs-test").unwrap();
+        let path = tmp.path().join("small.txt");
+        fs::File::create(&path).unwrap().write_all(b"hello").unwrap();
+
+        let file = fs::File::open(&path).unwrap();
+        let mmap = Mmap::open(&file, Protection::Read).unwrap();
+        let mut mapped = StdFile::Mapped { file: file, mmap: mmap, pos: 0 };
+
+        mapped.seek(SeekFrom::End(100)).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(mapped.read(&mut buf).unwrap(), 0);
     }
 }
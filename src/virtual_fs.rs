@@ -1,3 +1,4 @@
+use error::AfsError;
 use filesystem::*;
 use std::collections::BTreeMap;
 use std::error::Error;
@@ -75,6 +76,46 @@ impl VirtualFs {
     }
 
     pub fn unmount_all(&mut self) { self.mounted.clear() }
+
+    /// Resolves `from` and `to` against the mount table. If both land in
+    /// the *same* mounted backend, returns that backend and the two
+    /// paths relative to it, so the caller can take the fast native
+    /// path. Returns `Ok(None)` if they land in two different (but both
+    /// real) backends, so the caller should fall back to streaming
+    /// through `self`. Either path crossing a virtual (unmounted)
+    /// directory boundary is an error.
+    fn same_backend<'s>(
+        &'s self,
+        from: &Path,
+        to: &Path,
+    ) -> io::Result<Option<(&'s Filesystem, PathBuf, PathBuf)>> {
+        let mut from_match = None;
+        for (vbase, fs) in &self.mounted {
+            if let Ok(vrest) = from.strip_prefix(vbase) {
+                from_match = Some((vbase, fs, vrest.to_owned()));
+                break;
+            } else if vbase.starts_with(from) {
+                return permission_denied(from);
+            }
+        }
+        let (from_base, from_fs, from_rest) = match from_match {
+            Some(found) => found,
+            None => return not_found(from),
+        };
+
+        for (vbase, fs) in &self.mounted {
+            if let Ok(vrest) = to.strip_prefix(vbase) {
+                return if vbase == from_base {
+                    Ok(Some((from_fs.as_ref(), from_rest, vrest.to_owned())))
+                } else {
+                    Ok(None)
+                };
+            } else if vbase.starts_with(to) {
+                return permission_denied(to);
+            }
+        }
+        not_found(to)
+    }
 }
 
 const VDIR_META: Metadata = Metadata {
@@ -84,17 +125,19 @@ const VDIR_META: Metadata = Metadata {
     created: None,
     accessed: None,
     modified: None,
+    mode: None,
 };
 
-// TODO include path
-fn not_found<R>() -> io::Result<R> {
-    Err(io::Error::new(io::ErrorKind::NotFound, "")) // TODO? errmsg
+fn not_found<R>(vpath: &Path) -> io::Result<R> {
+    Err(AfsError::new(io::ErrorKind::NotFound, "no such file or directory", vpath).into())
 }
 
-// TODO include path
-fn permission_denied<R>() -> io::Result<R> {
-    // "virtual directories can only be modified through mounting & unmounting"
-    Err(io::Error::new(io::ErrorKind::PermissionDenied, "")) // TODO? errmsg
+fn permission_denied<R>(vpath: &Path) -> io::Result<R> {
+    Err(AfsError::new(
+        io::ErrorKind::PermissionDenied,
+        "virtual directories can only be modified through mounting & unmounting",
+        vpath,
+    ).into())
 }
 
 impl Filesystem for VirtualFs {
@@ -108,19 +151,19 @@ impl Filesystem for VirtualFs {
                 return Ok(VDIR_META);
             }
         }
-        not_found()
+        not_found(vpath)
     }
-    fn open_file(&self, vpath: &Path, opts: OpenOptions) -> io::Result<Box<File>> {
+    fn open_file(&self, vpath: &Path, opts: OpenOptions, mode: Option<u32>) -> io::Result<Box<File>> {
         let _ = super::validate_path(vpath)?;
 
         for (vbase, fs) in &self.mounted {
             if let Ok(vrest) = vpath.strip_prefix(vbase) {
-                return fs.open_file(vrest, opts);
+                return fs.open_file(vrest, opts, mode);
             } else if vbase.starts_with(vpath) {
-                return permission_denied();
+                return permission_denied(vpath);
             }
         }
-        not_found()
+        not_found(vpath)
     }
     fn remove_file(&self, vpath: &Path) -> io::Result<()> {
         let _ = super::validate_path(vpath)?;
@@ -129,10 +172,10 @@ impl Filesystem for VirtualFs {
             if let Ok(vrest) = vpath.strip_prefix(vbase) {
                 return fs.remove_file(vrest);
             } else if vbase.starts_with(vpath) {
-                return permission_denied();
+                return permission_denied(vpath);
             }
         }
-        not_found()
+        not_found(vpath)
     }
     fn read_dir(&self, vpath: &Path) -> io::Result<BTreeMap<PathBuf, Metadata>> {
         let _ = super::validate_path(vpath)?;
@@ -141,7 +184,17 @@ impl Filesystem for VirtualFs {
         for (vbase, fs) in &self.mounted {
             if let Ok(vrest) = vpath.strip_prefix(vbase) {
                 debug_assert!(ret.is_empty());
-                return fs.read_dir(vrest);
+                // The backend's keys are rooted at `vbase` (its own
+                // filesystem root), not at our virtual root, so they
+                // must be re-prefixed with `vbase` before they're valid
+                // paths to feed back into `self` (e.g. from `WalkDir`
+                // recursing past the top level of a mount).
+                return fs.read_dir(vrest).map(|entries| {
+                    entries
+                        .into_iter()
+                        .map(|(child, meta)| (vbase.join(child), meta))
+                        .collect()
+                });
             } else if let Ok(vdir) = vbase.strip_prefix(vpath) {
                 ret.insert(vdir.to_owned(), VDIR_META);
             }
@@ -149,28 +202,28 @@ impl Filesystem for VirtualFs {
         if !ret.is_empty() {
             Ok(ret)
         } else {
-            not_found()
+            not_found(vpath)
         }
     }
-    fn create_dir(&self, vpath: &Path) -> io::Result<()> {
+    fn create_dir(&self, vpath: &Path, mode: Option<u32>) -> io::Result<()> {
         let _ = super::validate_path(vpath)?;
 
         for (vbase, fs) in &self.mounted {
             if let Ok(vrest) = vpath.strip_prefix(vbase) {
-                return fs.create_dir(vrest);
+                return fs.create_dir(vrest, mode);
             }
         }
-        permission_denied()
+        permission_denied(vpath)
     }
-    fn create_dir_all(&self, vpath: &Path) -> io::Result<()> {
+    fn create_dir_all(&self, vpath: &Path, mode: Option<u32>) -> io::Result<()> {
         let _ = super::validate_path(vpath)?;
 
         for (vbase, fs) in &self.mounted {
             if let Ok(vrest) = vpath.strip_prefix(vbase) {
-                return fs.create_dir_all(vrest);
+                return fs.create_dir_all(vrest, mode);
             }
         }
-        permission_denied()
+        permission_denied(vpath)
     }
     fn remove_dir(&self, vpath: &Path) -> io::Result<()> {
         let _ = super::validate_path(vpath)?;
@@ -179,11 +232,12 @@ impl Filesystem for VirtualFs {
             if let Ok(vrest) = vpath.strip_prefix(vbase) {
                 return fs.remove_dir(vrest);
             } else if vbase.starts_with(vpath) {
-                return permission_denied();
+                return permission_denied(vpath);
             }
         }
-        not_found()
+        not_found(vpath)
     }
+
     fn remove_dir_all(&self, vpath: &Path) -> io::Result<()> {
         let _ = super::validate_path(vpath)?;
 
@@ -191,9 +245,74 @@ impl Filesystem for VirtualFs {
             if let Ok(vrest) = vpath.strip_prefix(vbase) {
                 return fs.remove_dir_all(vrest);
             } else if vbase.starts_with(vpath) {
-                return permission_denied();
+                return permission_denied(vpath);
             }
         }
-        not_found()
+        not_found(vpath)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let _ = super::validate_path(from)?;
+        let _ = super::validate_path(to)?;
+
+        match self.same_backend(from, to)? {
+            Some((fs, from_rest, to_rest)) => fs.copy(&from_rest, &to_rest),
+            None => generic_copy(self, from, to),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let _ = super::validate_path(from)?;
+        let _ = super::validate_path(to)?;
+
+        match self.same_backend(from, to)? {
+            Some((fs, from_rest, to_rest)) => fs.rename(&from_rest, &to_rest),
+            None => {
+                generic_copy(self, from, to)?;
+                if self.metadata(from)?.file_type.contains(DIRECTORY) {
+                    self.remove_dir_all(from)
+                } else {
+                    self.remove_file(from)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use std_fs::StdFs;
+
+    #[test]
+    fn walk_dir_crosses_mount_boundary() {
+        let base = PathBuf::from(format!("afs_test_walk_dir_{}", ::std::process::id()));
+        fs::create_dir_all(base.join("sub")).unwrap();
+        fs::File::create(base.join("top.txt")).unwrap().write_all(b"top").unwrap();
+        fs::File::create(base.join("sub/a.txt")).unwrap().write_all(b"a").unwrap();
+
+        let std_fs = StdFs::new(&base).unwrap();
+        let mut vfs = VirtualFs::new();
+        vfs.mount("mnt", std_fs).unwrap();
+
+        let mut seen: Vec<PathBuf> = vfs
+            .walk_dir(Path::new("mnt"))
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        seen.sort();
+
+        fs::remove_dir_all(&base).unwrap();
+
+        assert_eq!(
+            seen,
+            vec![
+                PathBuf::from("mnt/sub"),
+                PathBuf::from("mnt/sub/a.txt"),
+                PathBuf::from("mnt/top.txt"),
+            ]
+        );
     }
 }